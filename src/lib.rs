@@ -1,14 +1,21 @@
 extern crate nalgebra as na;
+extern crate rand;
 use na::{DVec, DMat, Norm, Transpose, Inv, Diag, Iterable};
+use rand::Rng;
 use std::iter::{FromIterator, Iterator, IntoIterator};
 
+// Cents by which a sampled interval target is perturbed during sensitivity
+// analysis, drawn uniformly from [-PERTURBATION_CENTS, PERTURBATION_CENTS].
+const PERTURBATION_CENTS: f64 = 10.0;
+
 struct OWTCriteria {
     title: &'static str,
     num_pitches: i32,
     repeat_factor: f64,
     ideal_intervals: DVec<f64>,
     interval_weights: DVec<f64>,
-    key_weights: DVec<f64>
+    key_weights: DVec<f64>,
+    lambda: f64
 }
 
 struct OWTResults {
@@ -16,6 +23,71 @@ struct OWTResults {
     optimal_tuning: Vec<f64>
 }
 
+#[derive(Debug, PartialEq)]
+enum OWTError {
+    SingularSystem
+}
+
+struct SensitivityResults {
+    mean_tuning: Vec<f64>,
+    variance_tuning: Vec<f64>,
+    mean_chisq: f64,
+    variance_chisq: f64
+}
+
+struct CrossValidationResults {
+    mean_held_out_error: f64,
+    stddev_held_out_error: f64
+}
+
+struct PitchSubsetResult {
+    pitch_indices: Vec<usize>,
+    results: OWTResults
+}
+
+// Vose's alias method: O(1) weighted sampling after an O(n) setup pass,
+// used to pick which ideal interval to perturb on each Monte Carlo draw.
+struct AliasTable {
+    prob: Vec<f64>,
+    alias: Vec<usize>
+}
+
+impl AliasTable {
+    fn new(weights: &[f64]) -> AliasTable {
+        let n = weights.len();
+        let total: f64 = weights.iter().fold(0.0, |acc, w| acc + w);
+        let mut scaled: Vec<f64> = weights.iter().map(|w| w * (n as f64) / total).collect();
+        let mut prob = vec![0.0; n];
+        let mut alias = vec![0usize; n];
+
+        let mut small: Vec<usize> = Vec::new();
+        let mut large: Vec<usize> = Vec::new();
+        for i in 0..n {
+            if scaled[i] < 1.0 { small.push(i) } else { large.push(i) }
+        }
+
+        while !small.is_empty() && !large.is_empty() {
+            let s = small.pop().unwrap();
+            let l = large.pop().unwrap();
+            prob[s] = scaled[s];
+            alias[s] = l;
+            scaled[l] = scaled[l] - (1.0 - scaled[s]);
+            if scaled[l] < 1.0 { small.push(l) } else { large.push(l) }
+        }
+
+        for i in small.into_iter().chain(large.into_iter()) {
+            prob[i] = 1.0;
+        }
+
+        AliasTable { prob: prob, alias: alias }
+    }
+
+    fn sample<R: Rng>(&self, rng: &mut R) -> usize {
+        let i = rng.gen_range(0, self.prob.len());
+        if rng.gen::<f64>() < self.prob[i] { i } else { self.alias[i] }
+    }
+}
+
 impl OWTCriteria {
     fn populate_source_matrix(&self) -> DMat<f64> {
         let nrows = self.num_pitches * (self.num_pitches - 1);
@@ -60,21 +132,264 @@ impl OWTCriteria {
         }))
     }
 
-    fn optimize_temperament(&self) -> DVec<f64> {
+    // Tikhonov-regularized solve: t = (AᵀWA + λI)⁻¹ AᵀW b. With `lambda`
+    // set to 0.0 and an invertible normal matrix this matches the
+    // unregularized least-squares fit exactly. If the (ncols x ncols)
+    // normal matrix is still singular at λ = 0, falls back to a
+    // vanishingly small ridge term as a numerical stand-in for the
+    // Moore-Penrose pseudoinverse of that same matrix — the Tikhonov
+    // limit (AᵀWA + εI)⁻¹ AᵀWb → pinv(AᵀWA) AᵀWb as ε → 0 — instead of
+    // unwinding. (Note: the (nrows x nrows) Gram matrix A Aᵀ is the wrong
+    // thing to invert here, since this system is overdetermined, not
+    // underdetermined, and A Aᵀ is rank-deficient by construction.)
+    fn optimize_temperament(&self) -> Result<DVec<f64>, OWTError> {
         let source_matrix = self.populate_source_matrix();
         let ideal_intervals_vector = self.populate_ideal_interval_vector();
         let weights_vector = DMat::from_diag(&self.populate_weights_vector());
-        match (&source_matrix.transpose() * &weights_vector * &source_matrix).inv() {
-            Some(x) => { x * &source_matrix.transpose() * &weights_vector * ideal_intervals_vector },
-            None => { panic!("What!!") }
+        let transposed = source_matrix.transpose();
+        let normal_matrix = &transposed * &weights_vector * &source_matrix;
+        let ncols = (self.num_pitches - 1) as usize;
+        let regularized = &normal_matrix + &(DMat::new_identity(ncols) * self.lambda);
+
+        match regularized.inv() {
+            Some(inv) => Ok(&inv * &transposed * &weights_vector * ideal_intervals_vector),
+            None => {
+                let pseudo_epsilon = 1.0e-10;
+                let pseudo_regularized = &normal_matrix + &(DMat::new_identity(ncols) * pseudo_epsilon);
+                match pseudo_regularized.inv() {
+                    Some(inv) => Ok(&inv * &transposed * &weights_vector * ideal_intervals_vector),
+                    None => Err(OWTError::SingularSystem)
+                }
+            }
         }
     }
+
+    // Solves for the optimal tuning and reports how well it satisfies the
+    // ideal intervals, weighted the same way the fit itself was weighted.
+    fn evaluate(&self) -> Result<OWTResults, OWTError> {
+        let source_matrix = self.populate_source_matrix();
+        let ideal_intervals_vector = self.populate_ideal_interval_vector();
+        let weights_vector = self.populate_weights_vector();
+        let optimal_tuning = match self.optimize_temperament() {
+            Ok(t) => t,
+            Err(e) => return Err(e)
+        };
+
+        let residual = &source_matrix * &optimal_tuning - ideal_intervals_vector;
+        let chisq = weights_vector.iter().zip(residual.iter())
+            .fold(0.0, |acc, (w, r)| acc + w * r * r);
+
+        Ok(OWTResults {
+            chisq: chisq,
+            optimal_tuning: optimal_tuning.iter().cloned().collect()
+        })
+    }
+
+    // Perturbs `ideal_intervals` `n_samples` times, drawing which interval
+    // to perturb with probability proportional to `interval_weights` via
+    // an alias table, and aggregates the resulting distribution of
+    // optimal tunings. Trials that land on a singular system are dropped;
+    // if every trial does, returns Err rather than a silent NaN mean.
+    fn sample_temperaments<R: Rng>(&self, rng: &mut R, n_samples: usize) -> Result<SensitivityResults, OWTError> {
+        let interval_weights: Vec<f64> = self.interval_weights.iter().cloned().collect();
+        let alias_table = AliasTable::new(&interval_weights);
+        let num_params = (self.num_pitches - 1) as usize;
+
+        let mut tunings: Vec<Vec<f64>> = Vec::with_capacity(n_samples);
+        let mut chisqs: Vec<f64> = Vec::with_capacity(n_samples);
+
+        for _ in 0..n_samples {
+            let perturbed_index = alias_table.sample(rng);
+            let mut perturbed_intervals = self.ideal_intervals.clone();
+            perturbed_intervals[perturbed_index] += (rng.gen::<f64>() * 2.0 - 1.0) * PERTURBATION_CENTS;
+
+            let trial = OWTCriteria {
+                title: self.title,
+                num_pitches: self.num_pitches,
+                repeat_factor: self.repeat_factor,
+                ideal_intervals: perturbed_intervals,
+                interval_weights: self.interval_weights.clone(),
+                key_weights: self.key_weights.clone(),
+                lambda: self.lambda
+            };
+
+            if let Ok(results) = trial.evaluate() {
+                tunings.push(results.optimal_tuning);
+                chisqs.push(results.chisq);
+            }
+        }
+
+        if tunings.is_empty() {
+            return Err(OWTError::SingularSystem);
+        }
+
+        let n = tunings.len() as f64;
+        let mut mean_tuning = vec![0.0; num_params];
+        for t in &tunings {
+            for i in 0..num_params {
+                mean_tuning[i] += t[i];
+            }
+        }
+        for m in mean_tuning.iter_mut() { *m /= n; }
+
+        let mut variance_tuning = vec![0.0; num_params];
+        for t in &tunings {
+            for i in 0..num_params {
+                let d = t[i] - mean_tuning[i];
+                variance_tuning[i] += d * d;
+            }
+        }
+        for v in variance_tuning.iter_mut() { *v /= n; }
+
+        let mean_chisq = chisqs.iter().fold(0.0, |acc, c| acc + c) / n;
+        let variance_chisq = chisqs.iter()
+            .fold(0.0, |acc, c| acc + (c - mean_chisq) * (c - mean_chisq)) / n;
+
+        Ok(SensitivityResults {
+            mean_tuning: mean_tuning,
+            variance_tuning: variance_tuning,
+            mean_chisq: mean_chisq,
+            variance_chisq: variance_chisq
+        })
+    }
+
+    // K-fold cross-validation over the rows of the source matrix: shuffles
+    // the rows, splits them into `k` near-equal folds, and for each fold
+    // refits with the held-out rows zeroed out of the weighting so their
+    // weighted squared error can be measured out-of-sample. Folds that
+    // leave a singular training system are skipped; if every fold does,
+    // returns Err rather than a silent NaN mean. `k` must be in
+    // `1..=nrows`: a fold beyond the row count would hold out nothing and
+    // record a phantom zero-error fold instead of a real one.
+    fn cross_validate<R: Rng>(&self, k: usize, rng: &mut R) -> Result<CrossValidationResults, OWTError> {
+        let ncols = (self.num_pitches - 1) as usize;
+        let nrows = (self.num_pitches * (self.num_pitches - 1)) as usize;
+
+        if k == 0 || k > nrows {
+            return Err(OWTError::SingularSystem);
+        }
+
+        let source_matrix = self.populate_source_matrix();
+        let ideal_intervals_vector = self.populate_ideal_interval_vector();
+        let base_weights = self.populate_weights_vector();
+
+        let mut row_order: Vec<usize> = (0..nrows).collect();
+        rng.shuffle(&mut row_order);
+
+        let mut errors: Vec<f64> = Vec::with_capacity(k);
+
+        for fold in 0..k {
+            let held_out: Vec<usize> = row_order.iter().enumerate()
+                .filter(|&(i, _)| i % k == fold)
+                .map(|(_, &row)| row)
+                .collect();
+
+            let mut train_weights = base_weights.clone();
+            for &row in &held_out {
+                train_weights[row] = 0.0;
+            }
+
+            let weights_diag = DMat::from_diag(&train_weights);
+            let transposed = source_matrix.transpose();
+            let normal_matrix = &transposed * &weights_diag * &source_matrix;
+            let regularized = &normal_matrix + &(DMat::new_identity(ncols) * self.lambda);
+
+            let tuning = match regularized.inv() {
+                Some(inv) => &inv * &transposed * &weights_diag * ideal_intervals_vector.clone(),
+                None => continue
+            };
+
+            let held_out_error = held_out.iter().fold(0.0, |acc, &row| {
+                let predicted = (0..ncols).fold(0.0, |acc, col| {
+                    acc + source_matrix[(row, col)] * tuning[col]
+                });
+                let residual = predicted - ideal_intervals_vector[row];
+                acc + base_weights[row] * residual * residual
+            });
+            errors.push(held_out_error);
+        }
+
+        if errors.is_empty() {
+            return Err(OWTError::SingularSystem);
+        }
+
+        let n = errors.len() as f64;
+        let mean = errors.iter().fold(0.0, |acc, e| acc + e) / n;
+        let variance = errors.iter().fold(0.0, |acc, e| acc + (e - mean) * (e - mean)) / n;
+
+        Ok(CrossValidationResults {
+            mean_held_out_error: mean,
+            stddev_held_out_error: variance.sqrt()
+        })
+    }
+
+    // Builds a reduced OWTCriteria over exactly the given, sorted pitch
+    // indices. `ideal_intervals[i]`/`interval_weights[i]` are always the
+    // target/weight for the interval between pitch 0 and pitch i + 1 (see
+    // populate_ideal_interval_vector), so pitch 0 is assumed to be among
+    // `pitch_indices` and each surviving pitch p > 0 keeps its original
+    // slot at `ideal_intervals[p - 1]`.
+    fn reduce_to_pitches(&self, pitch_indices: &[usize]) -> OWTCriteria {
+        let non_tonic: Vec<usize> = pitch_indices.iter().cloned().filter(|&p| p != 0).collect();
+        let ideal_intervals = DVec::<f64>::from_iter(non_tonic.iter().map(|&p| self.ideal_intervals[p - 1]));
+        let interval_weights = DVec::<f64>::from_iter(non_tonic.iter().map(|&p| self.interval_weights[p - 1]));
+        let key_weights = DVec::<f64>::from_iter(pitch_indices.iter().map(|&p| self.key_weights[p]));
+
+        OWTCriteria {
+            title: self.title,
+            num_pitches: pitch_indices.len() as i32,
+            repeat_factor: self.repeat_factor,
+            ideal_intervals: ideal_intervals,
+            interval_weights: interval_weights,
+            key_weights: key_weights,
+            lambda: self.lambda
+        }
+    }
+
+    // Weighted reservoir-style selection of `m` pitches out of
+    // `num_pitches`, favoring pitches with larger `key_weights`: pitch 0 is
+    // always kept, since it is the implicit tonic `ideal_intervals` is
+    // measured from, and gets no key; each other candidate gets a key
+    // u_i^(1/w_i) for u_i uniform in (0, 1), and the `m - 1` largest keys
+    // are kept. Builds and solves a reduced OWTCriteria over the survivors.
+    // `m` must be in `2..=num_pitches`: fewer than 2 pitches leave no
+    // interval to fit, and more than num_pitches doesn't exist to select.
+    fn choose_pitch_subset<R: Rng>(&self, m: usize, rng: &mut R) -> Result<PitchSubsetResult, OWTError> {
+        let n = self.num_pitches as usize;
+
+        if m < 2 || m > n {
+            return Err(OWTError::SingularSystem);
+        }
+
+        let mut keyed: Vec<(f64, usize)> = (1..n).map(|i| {
+            let u: f64 = rng.gen::<f64>();
+            let w = self.key_weights[i];
+            (u.powf(1.0 / w), i)
+        }).collect();
+
+        keyed.sort_by(|a, b| b.0.partial_cmp(&a.0).unwrap());
+        let mut pitch_indices: Vec<usize> = keyed.into_iter().take(m - 1).map(|(_, i)| i).collect();
+        pitch_indices.push(0);
+        pitch_indices.sort();
+
+        let reduced = self.reduce_to_pitches(&pitch_indices);
+        let results = match reduced.evaluate() {
+            Ok(r) => r,
+            Err(e) => return Err(e)
+        };
+
+        Ok(PitchSubsetResult {
+            pitch_indices: pitch_indices,
+            results: results
+        })
+    }
 }
 
 #[cfg(test)]
 mod tests {
-    use super::OWTCriteria;
+    use super::{OWTCriteria, AliasTable};
     use na::{DVec, DMat, Iterable};
+    use rand::{StdRng, SeedableRng};
 
     fn get_criteria() -> OWTCriteria {
         OWTCriteria {
@@ -83,7 +398,8 @@ mod tests {
             repeat_factor: 1200.0,
             ideal_intervals: DVec::from_slice(2, &vec![0.0, 702.0]),
             interval_weights: DVec::from_slice(2, &vec![1.0e-6, 1.0]),
-            key_weights: DVec::from_slice(3, &vec![1.0, 1.0e-4, 1.0])
+            key_weights: DVec::from_slice(3, &vec![1.0, 1.0e-4, 1.0]),
+            lambda: 0.0
         }
     }
 
@@ -121,9 +437,139 @@ mod tests {
     fn test_optimize_temperament() {
         let criteria = get_criteria();
         let exp = DVec::from_slice(2, &vec![204.059, 702.030]);
-        let res = criteria.optimize_temperament();
+        let res = criteria.optimize_temperament().unwrap();
+        for (e, r) in exp.iter().zip(res.iter()) {
+            assert!((e - r).abs() < 0.01);
+        }
+    }
+
+    #[test]
+    fn test_optimize_temperament_nonzero_lambda_changes_result() {
+        let mut criteria = get_criteria();
+        criteria.lambda = 0.001;
+        let exp = DVec::from_slice(2, &vec![202.952, 701.126]);
+        let res = criteria.optimize_temperament().unwrap();
         for (e, r) in exp.iter().zip(res.iter()) {
             assert!((e - r).abs() < 0.01);
         }
     }
+
+    #[test]
+    fn test_optimize_temperament_singular_falls_back_to_pseudoinverse() {
+        // interval_weights all zero makes the weighted normal matrix the
+        // zero matrix, which is singular at lambda = 0 regardless of
+        // num_pitches, forcing the pseudoinverse fallback.
+        let criteria = OWTCriteria {
+            title: "Singular",
+            num_pitches: 2,
+            repeat_factor: 1200.0,
+            ideal_intervals: DVec::from_slice(1, &vec![700.0]),
+            interval_weights: DVec::from_slice(1, &vec![0.0]),
+            key_weights: DVec::from_slice(2, &vec![1.0, 1.0]),
+            lambda: 0.0
+        };
+        let res = criteria.optimize_temperament().unwrap();
+        assert_eq!(res[0], 0.0);
+    }
+
+    #[test]
+    fn test_evaluate() {
+        let criteria = get_criteria();
+        let res = criteria.evaluate().unwrap();
+        let exp_tuning = vec![204.059, 702.030];
+        for (e, r) in exp_tuning.iter().zip(res.optimal_tuning.iter()) {
+            assert!((e - r).abs() < 0.01);
+        }
+        assert!((res.chisq - 8.9315).abs() < 0.01);
+    }
+
+    #[test]
+    fn test_alias_table_uniform_weights() {
+        let table = AliasTable::new(&[1.0, 1.0]);
+        assert_eq!(table.prob, vec![1.0, 1.0]);
+    }
+
+    #[test]
+    fn test_sample_temperaments() {
+        let criteria = get_criteria();
+        let mut rng = StdRng::from_seed(&[1, 2, 3, 4]);
+        let res = criteria.sample_temperaments(&mut rng, 50).unwrap();
+        assert_eq!(res.mean_tuning.len(), 2);
+        assert_eq!(res.variance_tuning.len(), 2);
+        assert!(res.mean_chisq >= 0.0);
+        assert!(res.variance_chisq >= 0.0);
+    }
+
+    #[test]
+    fn test_sample_temperaments_no_trials_returns_err() {
+        let criteria = get_criteria();
+        let mut rng = StdRng::from_seed(&[1, 2, 3, 4]);
+        let res = criteria.sample_temperaments(&mut rng, 0);
+        assert_eq!(res.err(), Some(super::OWTError::SingularSystem));
+    }
+
+    #[test]
+    fn test_cross_validate() {
+        let criteria = get_criteria();
+        let mut rng = StdRng::from_seed(&[1, 2, 3, 4]);
+        let res = criteria.cross_validate(3, &mut rng).unwrap();
+        assert!(res.mean_held_out_error >= 0.0);
+        assert!(res.stddev_held_out_error >= 0.0);
+    }
+
+    #[test]
+    fn test_cross_validate_no_folds_returns_err() {
+        let criteria = get_criteria();
+        let mut rng = StdRng::from_seed(&[1, 2, 3, 4]);
+        let res = criteria.cross_validate(0, &mut rng);
+        assert_eq!(res.err(), Some(super::OWTError::SingularSystem));
+    }
+
+    #[test]
+    fn test_cross_validate_too_many_folds_returns_err() {
+        // num_pitches = 3 gives nrows = 6; k = 7 can't produce a real fold
+        // for every index, so this must error instead of recording
+        // phantom zero-error folds for the rows beyond nrows.
+        let criteria = get_criteria();
+        let mut rng = StdRng::from_seed(&[1, 2, 3, 4]);
+        let res = criteria.cross_validate(7, &mut rng);
+        assert_eq!(res.err(), Some(super::OWTError::SingularSystem));
+    }
+
+    #[test]
+    fn test_choose_pitch_subset() {
+        let criteria = get_criteria();
+        let mut rng = StdRng::from_seed(&[1, 2, 3, 4]);
+        let res = criteria.choose_pitch_subset(2, &mut rng).unwrap();
+        assert_eq!(res.pitch_indices.len(), 2);
+        assert_eq!(res.pitch_indices[0], 0);
+        assert_eq!(res.results.optimal_tuning.len(), 1);
+    }
+
+    #[test]
+    fn test_choose_pitch_subset_too_few_returns_err() {
+        let criteria = get_criteria();
+        let mut rng = StdRng::from_seed(&[1, 2, 3, 4]);
+        assert_eq!(criteria.choose_pitch_subset(0, &mut rng).err(), Some(super::OWTError::SingularSystem));
+        assert_eq!(criteria.choose_pitch_subset(1, &mut rng).err(), Some(super::OWTError::SingularSystem));
+    }
+
+    #[test]
+    fn test_choose_pitch_subset_too_many_returns_err() {
+        let criteria = get_criteria();
+        let mut rng = StdRng::from_seed(&[1, 2, 3, 4]);
+        assert_eq!(criteria.choose_pitch_subset(4, &mut rng).err(), Some(super::OWTError::SingularSystem));
+    }
+
+    #[test]
+    fn test_reduce_to_pitches_maps_intervals_by_pitch_index() {
+        let criteria = get_criteria();
+        // Pitches 0 and 2 survive, so the single remaining interval must
+        // be the original pitch-0-to-pitch-2 entry at index 1 (702 cents),
+        // not the pitch-0-to-pitch-1 entry at index 0 (0 cents).
+        let reduced = criteria.reduce_to_pitches(&[0, 2]);
+        assert_eq!(reduced.ideal_intervals, DVec::from_slice(1, &vec![702.0]));
+        assert_eq!(reduced.interval_weights, DVec::from_slice(1, &vec![1.0]));
+        assert_eq!(reduced.key_weights, DVec::from_slice(2, &vec![1.0, 1.0]));
+    }
 }